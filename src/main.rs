@@ -3,20 +3,103 @@ extern crate zip;
 #[macro_use]
 extern crate clap;
 
+extern crate rust_htslib;
+
+extern crate rayon;
+
+extern crate flate2;
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
 use std::cmp::{Ordering, PartialEq, PartialOrd};
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, Read, Result, Write};
+use std::path::Path;
+use std::process;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
+use flate2::read::GzDecoder;
+use rayon::prelude::*;
+use rust_htslib::bcf::{Read as BcfRead, Reader as BcfReader};
 
 /// When are f32's considered equal?
 /// Needed to establish some flavor of total ordering on floats
 const F32_EPSILON: f32 = 0.00001;
+
+// 2-bit-per-base allele encoding plus a reserved "unknown" flag bit,
+// packed two bases to a byte. This is the hot inner type of match_bim,
+// whose allele comparison runs once per BIM/VCF variant per strand archive, so
+// packing at parse time turns allele comparison into a few integer ops
+// instead of four char comparisons.
+const BASE_A: u8 = 0b00;
+const BASE_C: u8 = 0b01;
+const BASE_G: u8 = 0b10;
+const BASE_T: u8 = 0b11;
+/// Set for indels and ambiguity codes so they never spuriously match.
+const BASE_UNKNOWN: u8 = 0b100;
+const BASE_FIELD_BITS: u8 = 3;
+const BASE_FIELD_MASK: u8 = 0b111;
+
+type PackedAlleles = u8;
+
+fn encode_base(c: char) -> u8 {
+    match c {
+        'A' => BASE_A,
+        'C' => BASE_C,
+        'G' => BASE_G,
+        'T' => BASE_T,
+        _ => BASE_UNKNOWN,
+    }
+}
+
+fn pack_alleles(a: char, b: char) -> PackedAlleles {
+    (encode_base(a) << BASE_FIELD_BITS) | encode_base(b)
+}
+
+fn has_unknown_base(packed: PackedAlleles) -> bool {
+    (packed & BASE_UNKNOWN != 0) || ((packed >> BASE_FIELD_BITS) & BASE_UNKNOWN != 0)
+}
+
+/// Swap the two bases within a packed pair, so set-equality can be
+/// checked without caring which allele came first.
+fn swap_alleles(packed: PackedAlleles) -> PackedAlleles {
+    let hi = (packed >> BASE_FIELD_BITS) & BASE_FIELD_MASK;
+    let lo = packed & BASE_FIELD_MASK;
+    (lo << BASE_FIELD_BITS) | hi
+}
+
+/// Strand-complement a packed allele pair: A<->T, C<->G fall out of
+/// XORing each base's 2-bit field with 0b11. The unknown flag bit sits
+/// outside that mask, so Unknown stays Unknown under complementation.
+fn complement_alleles(packed: PackedAlleles) -> PackedAlleles {
+    packed ^ 0b011_011
+}
+
+/// Number of bases that differ between two packed pairs (order
+/// preserved), or `None` if either side carries a non-ACGT base.
+fn allele_distance(a: PackedAlleles, b: PackedAlleles) -> Option<u32> {
+    if has_unknown_base(a) || has_unknown_base(b) {
+        return None;
+    }
+    let d = a ^ b;
+    Some(((d | (d >> 1)) & 0b001_001).count_ones())
+}
+
+/// Set-equality between two packed allele pairs, ignoring which base
+/// came first and never matching if either side is non-ACGT.
+fn match_set_packed(left: PackedAlleles, right: PackedAlleles) -> bool {
+    allele_distance(left, right) == Some(0) || allele_distance(left, swap_alleles(right)) == Some(0)
+}
+
 const EXTRACT_BUFFER_SIZE: usize = 1024 * 1024;
 
 /// The result structure for a single strand file match
+#[derive(Serialize)]
 struct MatchResult {
     /// Name of the actual strand file within the ZIP archive
     pub name: String,
@@ -34,14 +117,14 @@ struct SourceEntry {
     pub name: String,
     pub chromosome: u64,
     pub position: u64,
-    pub alleles: (char, char),
+    pub alleles: PackedAlleles,
 }
 
 #[derive(Clone)]
 struct VariantEntry {
     pub chromosome: u64,
     pub position: u64,
-    pub alleles: (char, char),
+    pub alleles: PackedAlleles,
     pub strand: char,
 }
 
@@ -87,7 +170,22 @@ impl PartialEq for MatchResult {
     }
 }
 
+/// Sentinel returned for contigs chromosome_to_number can't classify
+/// (alt/decoy contigs, unplaced scaffolds, ...), distinct from any real
+/// chromosome number so callers can skip these rather than silently
+/// equating them with chromosome 0.
+const UNKNOWN_CHROMOSOME: u64 = u64::MAX;
+
 fn chromosome_to_number(s: &str) -> u64 {
+    // BIM files use bare numbers/X/Y, but VCFs commonly prefix contigs
+    // with "chr" (and sometimes "CHR"); strip it before the usual dispatch.
+    // `get(..3)` (rather than indexing) avoids panicking on a string whose
+    // byte 3 falls inside a multi-byte char.
+    let s = match s.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("chr") => &s[3..],
+        _ => s,
+    };
+
     let n = if let Ok(num) = u64::from_str_radix(&s, 10) {
         num
     } else {
@@ -96,7 +194,7 @@ fn chromosome_to_number(s: &str) -> u64 {
             "Y" => 24,
             "XY" => 25,
             "M" | "MT" => 26,
-            _ => 0,
+            _ => UNKNOWN_CHROMOSOME,
         }
     };
 
@@ -111,7 +209,7 @@ fn read_bim(filename: &str) -> Result<Vec<SourceEntry>> {
         name: String::from(""),
         chromosome: 0,
         position: 0,
-        alleles: ('X', 'X'),
+        alleles: pack_alleles('X', 'X'),
     };
 
     // Data syntax:
@@ -123,23 +221,69 @@ fn read_bim(filename: &str) -> Result<Vec<SourceEntry>> {
         entry.chromosome = chromosome_to_number(l.next().unwrap());
         entry.name = l.next().unwrap().to_string();
         entry.position = u64::from_str_radix(l.nth(1).unwrap(), 10).unwrap();
-        entry.alleles.0 = l.next().unwrap().chars().next().unwrap();
-        entry.alleles.1 = l.next().unwrap().chars().next().unwrap();
+        let a0 = l.next().unwrap().chars().next().unwrap();
+        let a1 = l.next().unwrap().chars().next().unwrap();
+        entry.alleles = pack_alleles(a0, a1);
 
         names.push(entry.clone());
     }
     Ok(names)
 }
 
-// Read a list of ZIP files from the given directory.
-// Each ZIP file name has the directory name prepended
+// Reads a list of variants from a VCF/BCF file, via rust_htslib.
+// Record IDs become `name`, CHROM/POS become `chromosome`/`position`,
+// and the REF/ALT pair becomes `alleles` (multiallelic ALTs beyond the
+// first are ignored, same as a PLINK triple only ever carrying two).
+// An allele longer than one base (an indel) is packed as unknown.
+fn read_vcf(filename: &str) -> Result<Vec<SourceEntry>> {
+    let mut reader = BcfReader::from_path(filename)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let header = reader.header().clone();
+    let mut names: Vec<SourceEntry> = Vec::new();
+
+    for record_result in reader.records() {
+        let record = record_result.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        let chrom = record
+            .rid()
+            .and_then(|rid| header.rid2name(rid).ok())
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .unwrap_or_else(|| String::from("0"));
+
+        let alleles = record.alleles();
+        if alleles.len() < 2 {
+            continue;
+        }
+        let single_base = |allele: &[u8]| if allele.len() == 1 { allele[0] as char } else { 'X' };
+
+        let id = record.id();
+        let name = if id == b"." {
+            format!("{}:{}", chrom, record.pos() + 1)
+        } else {
+            String::from_utf8_lossy(&id).to_string()
+        };
+
+        names.push(SourceEntry {
+            name,
+            chromosome: chromosome_to_number(&chrom),
+            position: record.pos() as u64 + 1,
+            alleles: pack_alleles(single_base(alleles[0]), single_base(alleles[1])),
+        });
+    }
+
+    Ok(names)
+}
+
+// Read a list of strand sources from the given directory: ZIP archives,
+// or loose .strand/.strand.gz files. Each name has the directory name
+// prepended.
 fn get_zip_list(dirname: &str) -> Result<Vec<String>> {
     let entries = fs::read_dir(dirname)?;
     let mut names: Vec<String> = Vec::new();
     for entry in entries {
         let f = entry?;
         if let Ok(name) = f.file_name().into_string() {
-            if name.ends_with(".zip") {
+            if name.ends_with(".zip") || name.ends_with(".strand") || name.ends_with(".strand.gz") {
                 names.push(format!("{}/{}", dirname, name).to_string());
             }
         }
@@ -147,46 +291,87 @@ fn get_zip_list(dirname: &str) -> Result<Vec<String>> {
     Ok(names)
 }
 
-/// Find the strand file within a ZIP archive and extract the variant/position pairs
-fn read_variants_from_zip(filename: &str) -> Result<(String, HashMap<String, VariantEntry>)> {
-    let mut zip = zip::ZipArchive::new(File::open(filename)?)?;
+/// Wrap `reader` in a gzip decoder if its first bytes are the gzip magic
+/// number, otherwise pass it through unchanged. This lets callers treat
+/// plain and gzipped strand streams uniformly regardless of extension.
+fn maybe_gunzip<'a, R: Read + 'a>(reader: R) -> Result<Box<dyn BufRead + 'a>> {
+    let mut buffered = BufReader::new(reader);
+    let is_gzip = {
+        let peeked = buffered.fill_buf()?;
+        peeked.len() >= 2 && peeked[0] == 0x1f && peeked[1] == 0x8b
+    };
+
+    if is_gzip {
+        Ok(Box::new(BufReader::new(GzDecoder::new(buffered))))
+    } else {
+        Ok(Box::new(buffered))
+    }
+}
+
+/// Parses strand-format lines from any BufRead into name -> VariantEntry,
+/// regardless of whether the bytes came from a plain file, a gzip
+/// stream, or a ZIP member.
+fn parse_strand_lines(reader: Box<dyn BufRead + '_>) -> Result<HashMap<String, VariantEntry>> {
     let mut variants = HashMap::new();
-    let mut strand_file_name: String = String::new();
 
     let mut var: VariantEntry = VariantEntry {
         chromosome: 0,
         position: 0,
-        alleles: ('X', 'X'),
+        alleles: pack_alleles('X', 'X'),
         strand: 'X',
     };
 
-    for i in 0..zip.len() {
-        let mut file = zip.by_index(i).unwrap();
-        if file.name().ends_with(".strand") {
-            strand_file_name = file.name().to_string();
-            for line in BufReader::new(file).lines() {
-                let line = line?;
-                let mut l = line.split_whitespace();
-
-                let name = l.next().unwrap().to_string();
-                var.chromosome = chromosome_to_number(l.next().unwrap());
-                var.position = u64::from_str_radix(l.next().unwrap(), 10).unwrap_or(0);
-                l.next().unwrap_or("*");
-                var.strand = l.next().unwrap().chars().next().unwrap();
-                //                println!("{} {} {}", var.chromosome, var.position, var.strand);
-
-                // Not all strand files carry allele information
-                let mut alleles = l.next().unwrap_or("XX").chars();
-                var.alleles.0 = alleles.next().unwrap();
-                var.alleles.1 = alleles.next().unwrap();
-                variants.insert(name, var.clone());
+    for line in reader.lines() {
+        let line = line?;
+        let mut l = line.split_whitespace();
+
+        let name = l.next().unwrap().to_string();
+        var.chromosome = chromosome_to_number(l.next().unwrap());
+        var.position = u64::from_str_radix(l.next().unwrap(), 10).unwrap_or(0);
+        l.next().unwrap_or("*");
+        var.strand = l.next().unwrap().chars().next().unwrap();
+        //                println!("{} {} {}", var.chromosome, var.position, var.strand);
+
+        // Not all strand files carry allele information
+        let mut alleles = l.next().unwrap_or("XX").chars();
+        let a0 = alleles.next().unwrap();
+        let a1 = alleles.next().unwrap();
+        var.alleles = pack_alleles(a0, a1);
+        variants.insert(name, var.clone());
+    }
+
+    Ok(variants)
+}
+
+/// Find the strand file -- within a ZIP archive, or a loose .strand /
+/// .strand.gz file -- and extract the variant/position pairs. The inner
+/// and outer encodings are detected independently, so a `.strand.gz`
+/// member inside a plain `.zip` works the same as a loose gzipped file.
+fn read_variants_from_zip(filename: &str) -> Result<(String, HashMap<String, VariantEntry>)> {
+    if filename.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(File::open(filename)?)?;
+
+        for i in 0..zip.len() {
+            let file = zip.by_index(i).unwrap();
+            if file.name().ends_with(".strand") || file.name().ends_with(".strand.gz") {
+                let strand_file_name = file.name().to_string();
+                let reader = maybe_gunzip(file)?;
+                let variants = parse_strand_lines(reader)?;
+                // We don't need more than one strand file
+                return Ok((strand_file_name, variants));
             }
-            // We don't need more than one strand file
-            break;
         }
-    }
 
-    Ok((strand_file_name, variants))
+        Ok((String::new(), HashMap::new()))
+    } else {
+        let strand_file_name = Path::new(filename)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| filename.to_string());
+        let reader = maybe_gunzip(File::open(filename)?)?;
+        let variants = parse_strand_lines(reader)?;
+        Ok((strand_file_name, variants))
+    }
 }
 
 enum AlleleMatch {
@@ -196,36 +381,12 @@ enum AlleleMatch {
     Mismatch,
 }
 
-fn match_set(left: (char, char), right: (char, char)) -> bool {
-    (left.0 == right.0 || left.0 == right.1) && (left.1 == right.0 || left.1 == right.1)
-}
-
-fn flip_alleles(i: (char, char)) -> (char, char) {
-    let mut res: (char, char) = (' ', ' ');
-
-    res.0 = match i.0 {
-        'A' => 'T',
-        'C' => 'G',
-        'G' => 'C',
-        'T' => 'A',
-        _ => 'X',
-    };
-    res.1 = match i.1 {
-        'A' => 'T',
-        'C' => 'G',
-        'G' => 'C',
-        'T' => 'A',
-        _ => 'X',
-    };
-    res
-}
-
-fn match_alleles(left: (char, char), right: (char, char), strand: char) -> AlleleMatch {
-    if match_set(left, flip_alleles(left)) && match_set(right, flip_alleles(right)) {
+fn match_alleles(left: PackedAlleles, right: PackedAlleles, strand: char) -> AlleleMatch {
+    if match_set_packed(left, complement_alleles(left)) && match_set_packed(right, complement_alleles(right)) {
         AlleleMatch::ATCG
-    } else if match_set(left, right) {
+    } else if match_set_packed(left, right) {
         AlleleMatch::Original
-    } else if match_set(left, flip_alleles(right)) && strand == '-' {
+    } else if match_set_packed(left, complement_alleles(right)) && strand == '-' {
         AlleleMatch::Plus
     } else {
         AlleleMatch::Mismatch
@@ -266,7 +427,10 @@ fn match_bim(
         if let Some(strand) = variants.get(&bimentry.name) {
             name_matches += 1;
 
-            if (strand.position == bimentry.position) && (strand.chromosome == bimentry.chromosome)
+            if bimentry.chromosome != UNKNOWN_CHROMOSOME
+                && strand.chromosome != UNKNOWN_CHROMOSOME
+                && strand.position == bimentry.position
+                && strand.chromosome == bimentry.chromosome
             {
                 name_pos_matches += 1;
 
@@ -296,130 +460,459 @@ fn match_bim(
     res
 }
 
-// Extracts a strand file from the given ZIP archive
-// and dumps it to the current working directory by
-// its original name
-fn extract_strand(zipfile: &str) -> Result<()> {
-    let mut zip = zip::ZipArchive::new(File::open(zipfile)?)?;
+// Strip a trailing ".gz" so an extracted strand file always lands as a
+// plain `.strand`, regardless of how it was stored at the source.
+fn strip_gz_suffix(name: &str) -> String {
+    match name.strip_suffix(".gz") {
+        Some(stripped) => stripped.to_string(),
+        None => name.to_string(),
+    }
+}
 
+fn copy_decoded<R: Read>(mut reader: R, target: &mut File, buffer: &mut [u8]) -> Result<()> {
+    loop {
+        let size = reader.read(buffer)?;
+        if size == 0 {
+            break;
+        }
+        target.write_all(&buffer[0..size])?;
+    }
+    Ok(())
+}
+
+// Extracts a strand file from the given source -- a ZIP archive, or a
+// loose .strand/.strand.gz file -- and dumps it to the current working
+// directory by its original name, transparently decompressing it so the
+// extracted file is always a plain `.strand`.
+fn extract_strand(source: &str) -> Result<()> {
     let mut buffer = vec![0; EXTRACT_BUFFER_SIZE];
 
-    for i in 0..zip.len() {
-        let mut file = zip.by_index(i).unwrap();
-        if file.name().ends_with(".strand") {
-            let mut target = File::create(file.name())?;
-            while let Ok(size) = file.read(&mut buffer) {
-                if size == 0 {
-                    break;
-                }
-                target.write_all(&buffer[0..size])?;
+    if source.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(File::open(source)?)?;
+
+        for i in 0..zip.len() {
+            let file = zip.by_index(i).unwrap();
+            if file.name().ends_with(".strand") || file.name().ends_with(".strand.gz") {
+                let target_name = strip_gz_suffix(file.name());
+                let reader = maybe_gunzip(file)?;
+                let mut target = File::create(target_name)?;
+                copy_decoded(reader, &mut target, &mut buffer)?;
+                break;
             }
-            break;
         }
+    } else {
+        let strand_file_name = Path::new(source)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| source.to_string());
+        let target_name = strip_gz_suffix(&strand_file_name);
+        let reader = maybe_gunzip(File::open(source)?)?;
+        let mut target = File::create(target_name)?;
+        copy_decoded(reader, &mut target, &mut buffer)?;
     }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let matches = App::new(crate_name!())
+// Shared arguments for all three subcommands
+fn bim_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("bim")
+        .value_name("FILE")
+        .takes_value(true)
+        .required(true)
+        .help("PLINK .bim, or VCF/BCF file to guess the chip type for")
+}
+
+fn strandfolder_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("strandfolder")
+        .value_name("DIR")
+        .takes_value(true)
+        .required(true)
+        .help("Directory containing Will Rayner's strand archives")
+}
+
+fn verbose_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("verbose")
+        .short("v")
+        .long("verbose")
+        .help("Be verbose and print progress")
+}
+
+fn threads_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("threads")
+        .long("threads")
+        .value_name("N")
+        .takes_value(true)
+        .help("Number of threads to scan strand archives with (default: all cores)")
+}
+
+fn build_cli() -> App<'static, 'static> {
+    App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
-        .arg(
-            Arg::with_name("bim")
-                .value_name("FILE")
-                .takes_value(true)
-                .required(true)
-                .help("PLINK .bim file to guess the chip type for"),
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("match")
+                .about("Rank strand archives by how well they match the query variants")
+                .arg(bim_arg())
+                .arg(strandfolder_arg())
+                .arg(verbose_arg())
+                .arg(threads_arg())
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Write result table to FILE instead of stdout"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .value_name("FORMAT")
+                        .possible_values(&["tsv", "json"])
+                        .default_value("tsv")
+                        .help("Output format for the result table"),
+                ),
         )
-        .arg(
-            Arg::with_name("strandfolder")
-                .value_name("DIR")
-                .takes_value(true)
-                .required(true)
-                .help("Directory containing Will Rayner's strand archives"),
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Extract the N most promising strand files to the working directory")
+                .arg(bim_arg())
+                .arg(strandfolder_arg())
+                .arg(verbose_arg())
+                .arg(threads_arg())
+                .arg(
+                    Arg::with_name("count")
+                        .short("n")
+                        .long("count")
+                        .value_name("N")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Number of top strand files to extract"),
+                ),
         )
-        .arg(
-            Arg::with_name("verbose")
-                .short("v")
-                .long("verbose")
-                .help("Be verbose and print progress"),
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about(
+                    "Check that the top-ranked archive's position/allele agreement clears a \
+                     threshold, for use as a QC gate; exits nonzero otherwise",
+                )
+                .arg(bim_arg())
+                .arg(strandfolder_arg())
+                .arg(verbose_arg())
+                .arg(threads_arg())
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .takes_value(true)
+                        .value_name("RATE")
+                        .default_value("0.95")
+                        .help("Minimum position/allele agreement required of the top hit"),
+                ),
         )
-        .arg(
-            Arg::with_name("extract")
-                .short("e")
-                .long("extract")
-                .value_name("N")
-                .help("Extract the N most promising strand files to the local working directory")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("output")
-                .short("o")
-                .long("output")
-                .takes_value(true)
-                .value_name("FILE")
-                .help("Write result table to FILE instead of stdout"),
-        )
-        .get_matches();
+}
 
-    let verbose = matches.occurrences_of("verbose");
+// Reads the query variants, detecting VCF/BCF vs. BIM by extension, and
+// reports how many variants loaded (and how many carry an unrecognized
+// contig, which match_bim skips for position matching) in verbose mode.
+fn load_query(query_file: &str, verbose: u64) -> Result<Vec<SourceEntry>> {
+    let is_vcf = query_file.ends_with(".vcf")
+        || query_file.ends_with(".vcf.gz")
+        || query_file.ends_with(".bcf");
 
     if verbose > 0 {
-        println!("Reading BIM file...");
+        println!(
+            "Reading {} file...",
+            if is_vcf { "VCF/BCF" } else { "BIM" }
+        );
     }
-    let bim = read_bim(matches.value_of("bim").unwrap())?;
+    let bim = if is_vcf {
+        read_vcf(query_file)?
+    } else {
+        read_bim(query_file)?
+    };
     if verbose > 0 {
         println!("{} variants loaded.", bim.len());
+
+        let unknown_contigs = bim
+            .iter()
+            .filter(|entry| entry.chromosome == UNKNOWN_CHROMOSOME)
+            .count();
+        if unknown_contigs > 0 {
+            println!(
+                "{} variant(s) have an unrecognized contig/chromosome name and are skipped for position matching.",
+                unknown_contigs
+            );
+        }
     }
-    let ziplist = get_zip_list(matches.value_of("strandfolder").unwrap())?;
+
+    Ok(bim)
+}
+
+fn parse_threads(sub_matches: &clap::ArgMatches) -> usize {
+    u64::from_str_radix(sub_matches.value_of("threads").unwrap_or("0"), 10).unwrap() as usize
+}
+
+// Scans every strand source in `strandfolder` against `bim` in parallel,
+// returning the ranked results together with a lookup from strand file
+// name back to its source path (needed by `extract`).
+fn scan_strand_folder(
+    bim: &[SourceEntry],
+    strandfolder: &str,
+    threads: usize,
+    verbose: u64,
+) -> Result<(BinaryHeap<MatchResult>, HashMap<String, String>)> {
+    let ziplist = get_zip_list(strandfolder)?;
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    // Each source is opened, parsed and matched independently of the
+    // others, since match_bim only ever reads the shared `bim` slice.
+    let scanned: Vec<Result<(String, String, MatchResult)>> = pool.install(|| {
+        ziplist
+            .par_iter()
+            .map(|z| {
+                if verbose > 0 {
+                    println!("Scanning {}", z);
+                }
+
+                let (name, vars) = read_variants_from_zip(z)?;
+                let res = match_bim(bim, &name, &vars);
+                Ok((name, z.clone(), res))
+            })
+            .collect()
+    });
 
     let mut results = BinaryHeap::new();
     let mut strandmap: HashMap<String, String> = HashMap::new();
 
-    for z in ziplist {
-        if verbose > 0 {
-            println!("Scanning {}", z);
-        }
-
-        let (name, vars) = read_variants_from_zip(&z)?;
-        strandmap.insert(name.to_string(), z);
-        let res = match_bim(&bim, &name, &vars);
+    for entry in scanned {
+        let (name, z, res) = entry?;
+        strandmap.insert(name, z);
         results.push(res);
     }
 
-    let mut extract_strands =
-        u64::from_str_radix(matches.value_of("extract").unwrap_or("0"), 10).unwrap();
+    Ok((results, strandmap))
+}
+
+fn cmd_match(sub_matches: &clap::ArgMatches) -> Result<()> {
+    let verbose = sub_matches.occurrences_of("verbose");
+    let threads = parse_threads(sub_matches);
+
+    let bim = load_query(sub_matches.value_of("bim").unwrap(), verbose)?;
+    let (mut results, _strandmap) = scan_strand_folder(
+        &bim,
+        sub_matches.value_of("strandfolder").unwrap(),
+        threads,
+        verbose,
+    )?;
 
-    // Set output target
-    let mut out_writer: Box<Write> = match matches.value_of("output") {
+    let mut out_writer: Box<Write> = match sub_matches.value_of("output") {
         Some(filename) => Box::new(File::create(&filename)?),
         None => Box::new(io::stdout()),
     };
 
-    writeln!(&mut out_writer, "strand\tname_match_rate\tpos_match_rate\toriginal_match_rate\tplus_match_rate\tatcg_match_rate")?;
+    if sub_matches.value_of("format") == Some("json") {
+        let mut ranked = Vec::new();
+        while let Some(res) = results.pop() {
+            ranked.push(res);
+        }
+        let json = serde_json::to_string_pretty(&ranked)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        writeln!(&mut out_writer, "{}", json)?;
+    } else {
+        writeln!(&mut out_writer, "strand\tname_match_rate\tpos_match_rate\toriginal_match_rate\tplus_match_rate\tatcg_match_rate")?;
+
+        while let Some(res) = results.pop() {
+            writeln!(
+                &mut out_writer,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                res.name,
+                res.name_match_rate,
+                res.name_pos_match_rate,
+                res.strand_match_rate,
+                res.strand_match_rate + res.plus_match_rate,
+                res.atcg_match_rate
+            )?;
+        }
+    }
 
-    while let Some(res) = results.pop() {
-        if extract_strands > 0 {
-            if verbose > 0 {
-                println!("Extracting {} from {}", &res.name, &strandmap[&res.name]);
-            }
-            extract_strand(&strandmap[&res.name])?;
-            extract_strands -= 1;
+    Ok(())
+}
+
+fn cmd_extract(sub_matches: &clap::ArgMatches) -> Result<()> {
+    let verbose = sub_matches.occurrences_of("verbose");
+    let threads = parse_threads(sub_matches);
+    let mut remaining = u64::from_str_radix(sub_matches.value_of("count").unwrap(), 10).unwrap();
+
+    let bim = load_query(sub_matches.value_of("bim").unwrap(), verbose)?;
+    let (mut results, strandmap) = scan_strand_folder(
+        &bim,
+        sub_matches.value_of("strandfolder").unwrap(),
+        threads,
+        verbose,
+    )?;
+
+    while remaining > 0 {
+        let res = match results.pop() {
+            Some(res) => res,
+            None => break,
+        };
+
+        if verbose > 0 {
+            println!("Extracting {} from {}", &res.name, &strandmap[&res.name]);
+        }
+        extract_strand(&strandmap[&res.name])?;
+        remaining -= 1;
+    }
+
+    Ok(())
+}
+
+fn cmd_verify(sub_matches: &clap::ArgMatches) -> Result<()> {
+    let verbose = sub_matches.occurrences_of("verbose");
+    let threads = parse_threads(sub_matches);
+    let threshold: f32 = sub_matches
+        .value_of("threshold")
+        .unwrap()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "threshold must be a number"))?;
+
+    let bim = load_query(sub_matches.value_of("bim").unwrap(), verbose)?;
+    let (mut results, _strandmap) = scan_strand_folder(
+        &bim,
+        sub_matches.value_of("strandfolder").unwrap(),
+        threads,
+        verbose,
+    )?;
+
+    let top = match results.pop() {
+        Some(res) => res,
+        None => {
+            eprintln!("No strand archives were scanned; nothing to verify.");
+            process::exit(1);
         }
+    };
 
-        writeln!(
-            &mut out_writer,
-            "{}\t{}\t{}\t{}\t{}\t{}",
-            res.name,
-            res.name_match_rate,
-            res.name_pos_match_rate,
-            res.strand_match_rate,
-            res.strand_match_rate + res.plus_match_rate,
-            res.atcg_match_rate
-        )?;
+    // Fraction of name-matched variants that also agree on position, times
+    // the fraction of those (not counting strand-ambiguous A/T and C/G
+    // variants) that agree on the allele pair, directly or on the opposite
+    // strand.
+    let agreement = top.name_pos_match_rate * (top.strand_match_rate + top.plus_match_rate);
+
+    if verbose > 0 {
+        println!(
+            "Top hit: {} (position/allele agreement: {:.4})",
+            top.name, agreement
+        );
     }
 
+    if agreement + F32_EPSILON < threshold {
+        eprintln!(
+            "Top hit '{}' has a position/allele agreement of {:.4}, below the required threshold of {:.4}.",
+            top.name, agreement, threshold
+        );
+        process::exit(1);
+    }
+
+    println!(
+        "OK: '{}' clears the {:.4} threshold with an agreement of {:.4}.",
+        top.name, threshold, agreement
+    );
+
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let matches = build_cli().get_matches();
+
+    match matches.subcommand() {
+        ("match", Some(sub_matches)) => cmd_match(sub_matches),
+        ("extract", Some(sub_matches)) => cmd_extract(sub_matches),
+        ("verify", Some(sub_matches)) => cmd_verify(sub_matches),
+        _ => unreachable!("clap enforces a subcommand via SubcommandRequiredElseHelp"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn write_gz(path: &std::path::Path, contents: &[u8]) {
+        let file = File::create(path).unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        encoder.write_all(contents).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    fn write_zip_with_strand(path: &std::path::Path, member_name: &str, contents: &[u8]) {
+        let file = File::create(path).unwrap();
+        let mut zip = zip::ZipWriter::new(file);
+        zip.start_file(member_name, zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(contents).unwrap();
+        zip.finish().unwrap();
+    }
+
+    // extract_strand must handle every source shape get_zip_list can
+    // hand it: a ZIP whose member is gzipped, a loose .strand, and a
+    // loose .strand.gz -- all landing as a plain .strand on disk.
+    #[test]
+    fn extract_strand_handles_zip_loose_and_gzip_sources() {
+        let strand_contents = b"rs1 1 1000 rs1 + A C\n";
+
+        let dir = std::env::temp_dir().join(format!(
+            "chipmatch-extract-test-{}-{}",
+            std::process::id(),
+            "mixed-sources"
+        ));
+        let sources_dir = dir.join("sources");
+        let work_dir = dir.join("work");
+        fs::create_dir_all(&sources_dir).unwrap();
+        fs::create_dir_all(&work_dir).unwrap();
+
+        let mut gz_encoder = GzEncoder::new(Vec::new(), Compression::default());
+        gz_encoder.write_all(strand_contents).unwrap();
+        let gzipped_contents = gz_encoder.finish().unwrap();
+
+        let zip_path = sources_dir.join("chip_a.zip");
+        write_zip_with_strand(&zip_path, "chip_a.strand.gz", &gzipped_contents);
+
+        let loose_path = sources_dir.join("chip_b.strand");
+        fs::write(&loose_path, strand_contents).unwrap();
+
+        let loose_gz_path = sources_dir.join("chip_c.strand.gz");
+        write_gz(&loose_gz_path, strand_contents);
+
+        let previous_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&work_dir).unwrap();
+        let result = (|| -> Result<()> {
+            extract_strand(zip_path.to_str().unwrap())?;
+            extract_strand(loose_path.to_str().unwrap())?;
+            extract_strand(loose_gz_path.to_str().unwrap())?;
+            Ok(())
+        })();
+        std::env::set_current_dir(&previous_dir).unwrap();
+
+        result.unwrap();
+
+        assert!(work_dir.join("chip_a.strand").exists());
+        assert!(work_dir.join("chip_b.strand").exists());
+        assert!(work_dir.join("chip_c.strand").exists());
+
+        assert_eq!(
+            fs::read(work_dir.join("chip_a.strand")).unwrap(),
+            strand_contents
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}